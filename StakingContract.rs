@@ -6,10 +6,132 @@ use solana_program::{
     program_error::ProgramError,
     pubkey::Pubkey,
     program_pack::{IsInitialized, Pack, Sealed},
-    sysvar::{rent::Rent, Sysvar},
+    sysvar::{clock::Clock, rent::Rent, stake_history, Sysvar},
     program::{invoke, invoke_signed},
+    stake::{self, instruction as stake_instruction},
     system_instruction,
 };
+use spl_token::instruction as token_instruction;
+
+/// Seed for the deposit-authority PDA, whose bump is stored as `deposit_bump_seed`.
+/// Deposits are signed by the depositing user, not by this PDA.
+pub const AUTHORITY_DEPOSIT: &[u8] = b"deposit";
+/// Seed used to derive the PDA that signs withdrawals (unstakes and reward claims) out of the vaults.
+pub const AUTHORITY_WITHDRAW: &[u8] = b"withdraw";
+
+/// Fixed-point scaling factor for `acc_reward_per_token`, so per-token rewards don't
+/// truncate to zero between updates.
+pub const PRECISION: u128 = 1_000_000_000_000;
+
+/// Seed prefix for a user's canonical `UserStakeInfo` PDA.
+pub const USER_STAKE_SEED: &[u8] = b"user";
+
+/// Errors specific to staking rules that don't map onto a built-in `ProgramError` variant.
+#[derive(Clone, Copy, Debug)]
+pub enum StakingError {
+    /// `unstake` was called before `lockup_duration` elapsed since the stake was deposited.
+    StakeLocked,
+}
+
+impl From<StakingError> for ProgramError {
+    fn from(e: StakingError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+/// Derives a program authority address, mirroring the stake-pool processor's
+/// `AUTHORITY_DEPOSIT` / `AUTHORITY_WITHDRAW` scheme.
+pub fn authority_id(
+    program_id: &Pubkey,
+    manager_key: &Pubkey,
+    seed: &[u8],
+    bump: u8,
+) -> Result<Pubkey, ProgramError> {
+    Pubkey::create_program_address(&[manager_key.as_ref(), seed, &[bump]], program_id)
+        .map_err(|_| ProgramError::InvalidSeeds)
+}
+
+/// Confirms `user_stake_account` is the canonical PDA for a given `(user, staking_manager,
+/// position_id)`, closing the gap where a caller substitutes someone else's (or a fake)
+/// stake account. `position_id` is `0` for a user's primary position and nonzero for the
+/// extra positions `split` creates.
+fn validate_user_stake_account(
+    program_id: &Pubkey,
+    user_key: &Pubkey,
+    staking_manager_key: &Pubkey,
+    position_id: u8,
+    user_stake_account: &Pubkey,
+) -> ProgramResult {
+    let (expected, _bump) = Pubkey::find_program_address(
+        &[
+            USER_STAKE_SEED,
+            user_key.as_ref(),
+            staking_manager_key.as_ref(),
+            &[position_id],
+        ],
+        program_id,
+    );
+    if expected != *user_stake_account {
+        msg!("User stake account is not the expected PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok(())
+}
+
+/// Confirms a token account is owned by the SPL token program and holds the expected mint.
+fn validate_token_account(token_account: &AccountInfo, expected_mint: &Pubkey) -> ProgramResult {
+    if token_account.owner != &spl_token::id() {
+        msg!("Token account is not owned by the SPL token program");
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let token_account_data = spl_token::state::Account::unpack(&token_account.data.borrow())?;
+    if token_account_data.mint != *expected_mint {
+        msg!("Token account mint does not match the expected mint");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
+}
+
+/// Accrues reward since `last_update_time` into `acc_reward_per_token`, clamped to the
+/// active epoch's window. Must run before any action that reads or changes stake so that
+/// `reward_debt`/`pending_reward` settlement is always against an up-to-date accumulator.
+fn update_pool(staking_manager: &mut StakingManager) -> ProgramResult {
+    let now = Clock::get()?.unix_timestamp as u64;
+
+    let epoch_start = staking_manager.current_epoch_start_time;
+    let epoch_end = staking_manager.current_epoch_end_time;
+    let window_start = staking_manager.last_update_time.max(epoch_start);
+    let window_end = now.min(epoch_end);
+
+    if staking_manager.tokens_staked > 0 && epoch_end > epoch_start && window_end > window_start {
+        let elapsed = (window_end - window_start) as u128;
+        let epoch_duration = (epoch_end - epoch_start) as u128;
+        let accrued = staking_manager.current_epoch_reward as u128 * elapsed / epoch_duration;
+        staking_manager.acc_reward_per_token +=
+            accrued * PRECISION / staking_manager.tokens_staked as u128;
+    }
+
+    staking_manager.last_update_time = now;
+    Ok(())
+}
+
+/// Moves a user's share of reward accrued since their last settlement into
+/// `pending_reward`. Must be called with the user's *current* `staked_amount`, before it
+/// is changed by a deposit or unstake.
+fn accrue_pending(staking_manager: &StakingManager, user_stake_info: &mut UserStakeInfo) {
+    let earned = user_stake_info.staked_amount as u128 * staking_manager.acc_reward_per_token / PRECISION;
+    let accrued = earned.saturating_sub(user_stake_info.reward_debt) as u64;
+    user_stake_info.pending_reward = user_stake_info.pending_reward.saturating_add(accrued);
+}
+
+/// Re-baselines `reward_debt` against the user's (possibly just-changed) `staked_amount`,
+/// so future settlements only count reward accrued from this point on.
+fn reset_reward_debt(staking_manager: &StakingManager, user_stake_info: &mut UserStakeInfo) {
+    user_stake_info.reward_debt =
+        user_stake_info.staked_amount as u128 * staking_manager.acc_reward_per_token / PRECISION;
+}
 
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct StakingManager {
@@ -22,6 +144,15 @@ pub struct StakingManager {
     pub current_epoch_start_time: u64,
     pub current_epoch_end_time: u64,
     pub epoch_id: u16,
+    pub deposit_bump_seed: u8,
+    pub withdraw_bump_seed: u8,
+    pub acc_reward_per_token: u128,
+    pub last_update_time: u64,
+    pub lockup_duration: u64,
+    pub stake_account: Pubkey,
+    pub vote_account: Pubkey,
+    pub stake_vault: Pubkey,
+    pub reward_vault: Pubkey,
 }
 
 impl Sealed for StakingManager {}
@@ -33,7 +164,7 @@ impl IsInitialized for StakingManager {
 }
 
 impl Pack for StakingManager {
-    const LEN: usize = 1 + 32 + 32 + 32 + 8 + 8 + 8 + 8 + 2;
+    const LEN: usize = 1 + 32 + 32 + 32 + 8 + 8 + 8 + 8 + 2 + 1 + 1 + 16 + 8 + 8 + 32 + 32 + 32 + 32;
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let dst = array_mut_ref![dst, 0, StakingManager::LEN];
         let (
@@ -46,7 +177,16 @@ impl Pack for StakingManager {
             current_epoch_start_time_dst,
             current_epoch_end_time_dst,
             epoch_id_dst,
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 8, 8, 8, 8, 2];
+            deposit_bump_seed_dst,
+            withdraw_bump_seed_dst,
+            acc_reward_per_token_dst,
+            last_update_time_dst,
+            lockup_duration_dst,
+            stake_account_dst,
+            vote_account_dst,
+            stake_vault_dst,
+            reward_vault_dst,
+        ) = mut_array_refs![dst, 1, 32, 32, 32, 8, 8, 8, 8, 2, 1, 1, 16, 8, 8, 32, 32, 32, 32];
 
         is_initialized_dst[0] = self.is_initialized as u8;
         owner_dst.copy_from_slice(self.owner.as_ref());
@@ -57,6 +197,15 @@ impl Pack for StakingManager {
         *current_epoch_start_time_dst = self.current_epoch_start_time.to_le_bytes();
         *current_epoch_end_time_dst = self.current_epoch_end_time.to_le_bytes();
         *epoch_id_dst = self.epoch_id.to_le_bytes();
+        deposit_bump_seed_dst[0] = self.deposit_bump_seed;
+        withdraw_bump_seed_dst[0] = self.withdraw_bump_seed;
+        *acc_reward_per_token_dst = self.acc_reward_per_token.to_le_bytes();
+        *last_update_time_dst = self.last_update_time.to_le_bytes();
+        *lockup_duration_dst = self.lockup_duration.to_le_bytes();
+        stake_account_dst.copy_from_slice(self.stake_account.as_ref());
+        vote_account_dst.copy_from_slice(self.vote_account.as_ref());
+        stake_vault_dst.copy_from_slice(self.stake_vault.as_ref());
+        reward_vault_dst.copy_from_slice(self.reward_vault.as_ref());
     }
 
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
@@ -71,7 +220,16 @@ impl Pack for StakingManager {
             current_epoch_start_time,
             current_epoch_end_time,
             epoch_id,
-        ) = array_refs![src, 1, 32, 32, 32, 8, 8, 8, 8, 2];
+            deposit_bump_seed,
+            withdraw_bump_seed,
+            acc_reward_per_token,
+            last_update_time,
+            lockup_duration,
+            stake_account,
+            vote_account,
+            stake_vault,
+            reward_vault,
+        ) = array_refs![src, 1, 32, 32, 32, 8, 8, 8, 8, 2, 1, 1, 16, 8, 8, 32, 32, 32, 32];
 
         Ok(StakingManager {
             is_initialized: is_initialized[0] != 0,
@@ -83,6 +241,15 @@ impl Pack for StakingManager {
             current_epoch_start_time: u64::from_le_bytes(*current_epoch_start_time),
             current_epoch_end_time: u64::from_le_bytes(*current_epoch_end_time),
             epoch_id: u16::from_le_bytes(*epoch_id),
+            deposit_bump_seed: deposit_bump_seed[0],
+            withdraw_bump_seed: withdraw_bump_seed[0],
+            acc_reward_per_token: u128::from_le_bytes(*acc_reward_per_token),
+            last_update_time: u64::from_le_bytes(*last_update_time),
+            lockup_duration: u64::from_le_bytes(*lockup_duration),
+            stake_account: Pubkey::new_from_array(*stake_account),
+            vote_account: Pubkey::new_from_array(*vote_account),
+            stake_vault: Pubkey::new_from_array(*stake_vault),
+            reward_vault: Pubkey::new_from_array(*reward_vault),
         })
     }
 }
@@ -92,29 +259,46 @@ pub struct UserStakeInfo {
     pub is_initialized: bool,
     pub user: Pubkey,
     pub staked_amount: u64,
+    pub reward_debt: u128,
+    pub pending_reward: u64,
+    pub stake_timestamp: u64,
 }
 
 impl Sealed for UserStakeInfo {}
 
 impl Pack for UserStakeInfo {
-    const LEN: usize = 1 + 32 + 8;
+    const LEN: usize = 1 + 32 + 8 + 16 + 8 + 8;
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let dst = array_mut_ref![dst, 0, UserStakeInfo::LEN];
-        let (is_initialized_dst, user_dst, staked_amount_dst) = mut_array_refs![dst, 1, 32, 8];
+        let (
+            is_initialized_dst,
+            user_dst,
+            staked_amount_dst,
+            reward_debt_dst,
+            pending_reward_dst,
+            stake_timestamp_dst,
+        ) = mut_array_refs![dst, 1, 32, 8, 16, 8, 8];
 
         is_initialized_dst[0] = self.is_initialized as u8;
         user_dst.copy_from_slice(self.user.as_ref());
         *staked_amount_dst = self.staked_amount.to_le_bytes();
+        *reward_debt_dst = self.reward_debt.to_le_bytes();
+        *pending_reward_dst = self.pending_reward.to_le_bytes();
+        *stake_timestamp_dst = self.stake_timestamp.to_le_bytes();
     }
 
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
         let src = array_ref![src, 0, UserStakeInfo::LEN];
-        let (is_initialized, user, staked_amount) = array_refs![src, 1, 32, 8];
+        let (is_initialized, user, staked_amount, reward_debt, pending_reward, stake_timestamp) =
+            array_refs![src, 1, 32, 8, 16, 8, 8];
 
         Ok(UserStakeInfo {
             is_initialized: is_initialized[0] != 0,
             user: Pubkey::new_from_array(*user),
             staked_amount: u64::from_le_bytes(*staked_amount),
+            reward_debt: u128::from_le_bytes(*reward_debt),
+            pending_reward: u64::from_le_bytes(*pending_reward),
+            stake_timestamp: u64::from_le_bytes(*stake_timestamp),
         })
     }
 }
@@ -142,10 +326,15 @@ fn process_instruction(
 
     match instruction_data[0] {
         0 => initialize(accounts, &mut staking_manager, instruction_data)?,
-        1 => deposit(accounts, &mut staking_manager, instruction_data)?,
-        2 => unstake(accounts, &mut staking_manager, instruction_data)?,
+        1 => deposit(program_id, accounts, &mut staking_manager, instruction_data)?,
+        2 => unstake(program_id, accounts, &mut staking_manager, instruction_data)?,
         3 => start_epoch(accounts, &mut staking_manager, instruction_data)?,
-        4 => claim(accounts, &mut staking_manager, instruction_data)?,
+        4 => claim(program_id, accounts, &mut staking_manager, instruction_data)?,
+        5 => split(program_id, accounts, &mut staking_manager, instruction_data)?,
+        6 => merge(program_id, accounts, &mut staking_manager, instruction_data)?,
+        7 => delegate_all(program_id, accounts, &mut staking_manager, instruction_data)?,
+        8 => deactivate(program_id, accounts, &mut staking_manager, instruction_data)?,
+        9 => reclaim(program_id, accounts, &mut staking_manager, instruction_data)?,
         _ => return Err(ProgramError::InvalidInstructionData),
     }
 
@@ -160,48 +349,115 @@ fn initialize(
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let owner_account = next_account_info(account_info_iter)?;
+    let stake_vault_account = next_account_info(account_info_iter)?;
+    let reward_vault_account = next_account_info(account_info_iter)?;
 
     if !owner_account.is_signer {
         msg!("Owner must be a signer");
         return Err(ProgramError::MissingRequiredSignature);
     }
 
+    let deposit_bump_seed = instruction_data[1];
+    let withdraw_bump_seed = instruction_data[2];
+    let lockup_duration = u64::from_le_bytes(instruction_data[3..11].try_into().unwrap());
+
+    // The stake/reward mints are derived from the vaults themselves rather than taken as
+    // separate instruction data, so they can never drift from what the vaults actually hold.
+    let stake_mint = spl_token::state::Account::unpack(&stake_vault_account.data.borrow())?.mint;
+    let reward_mint = spl_token::state::Account::unpack(&reward_vault_account.data.borrow())?.mint;
+
     staking_manager.is_initialized = true;
     staking_manager.owner = *owner_account.key;
+    staking_manager.deposit_bump_seed = deposit_bump_seed;
+    staking_manager.withdraw_bump_seed = withdraw_bump_seed;
+    staking_manager.lockup_duration = lockup_duration;
+    staking_manager.stake_token = stake_mint;
+    staking_manager.reward_token = reward_mint;
+    // Pin the vault accounts now, so deposit/unstake/claim can reject any other
+    // same-mint token account a caller tries to substitute in those slots later.
+    staking_manager.stake_vault = *stake_vault_account.key;
+    staking_manager.reward_vault = *reward_vault_account.key;
     // Parse additional initialization data if needed
 
     Ok(())
 }
 
 fn deposit(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
     staking_manager: &mut StakingManager,
     instruction_data: &[u8],
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
+    let staking_manager_account = next_account_info(account_info_iter)?;
     let user_account = next_account_info(account_info_iter)?;
-    let stake_token_account = next_account_info(account_info_iter)?;
+    let user_token_account = next_account_info(account_info_iter)?;
+    let stake_vault_account = next_account_info(account_info_iter)?;
     let user_stake_account = next_account_info(account_info_iter)?;
+    let token_program_account = next_account_info(account_info_iter)?;
 
     if !user_account.is_signer {
         msg!("User must be a signer");
         return Err(ProgramError::MissingRequiredSignature);
     }
 
+    validate_user_stake_account(
+        program_id,
+        user_account.key,
+        staking_manager_account.key,
+        0,
+        user_stake_account.key,
+    )?;
+    validate_token_account(user_token_account, &staking_manager.stake_token)?;
+    validate_token_account(stake_vault_account, &staking_manager.stake_token)?;
+    if *stake_vault_account.key != staking_manager.stake_vault {
+        msg!("Stake vault account does not match the pool's stake vault");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if token_program_account.key != &spl_token::id() {
+        msg!("Token program account does not match the SPL token program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
     let amount = u64::from_le_bytes(instruction_data[1..9].try_into().unwrap());
 
+    update_pool(staking_manager)?;
+
     let mut user_stake_info = UserStakeInfo::unpack_unchecked(&user_stake_account.data.borrow())?;
     if !user_stake_info.is_initialized {
         user_stake_info.is_initialized = true;
         user_stake_info.user = *user_account.key;
+        user_stake_info.reward_debt = 0;
+        user_stake_info.pending_reward = 0;
+    } else if user_stake_info.user != *user_account.key {
+        msg!("User stake account does not belong to the signer");
+        return Err(ProgramError::IllegalOwner);
     }
 
+    accrue_pending(staking_manager, &mut user_stake_info);
     user_stake_info.staked_amount += amount;
+    reset_reward_debt(staking_manager, &mut user_stake_info);
+    // Each deposit restarts the lockup window for the user's full (now-larger) position.
+    user_stake_info.stake_timestamp = Clock::get()?.unix_timestamp as u64;
     UserStakeInfo::pack(user_stake_info, &mut user_stake_account.data.borrow_mut())?;
 
+    // The user owns the source token account, so this CPI is signed by the user
+    // directly; withdrawals out of the vault below are what require the PDA authority.
     invoke(
-        &system_instruction::transfer(user_account.key, stake_token_account.key, amount),
-        &[user_account.clone(), stake_token_account.clone()],
+        &token_instruction::transfer(
+            token_program_account.key,
+            user_token_account.key,
+            stake_vault_account.key,
+            user_account.key,
+            &[],
+            amount,
+        )?,
+        &[
+            user_token_account.clone(),
+            stake_vault_account.clone(),
+            user_account.clone(),
+            token_program_account.clone(),
+        ],
     )?;
 
     staking_manager.tokens_staked += amount;
@@ -211,34 +467,101 @@ fn deposit(
 }
 
 fn unstake(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
     staking_manager: &mut StakingManager,
     instruction_data: &[u8],
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
+    let staking_manager_account = next_account_info(account_info_iter)?;
     let user_account = next_account_info(account_info_iter)?;
-    let stake_token_account = next_account_info(account_info_iter)?;
+    let user_token_account = next_account_info(account_info_iter)?;
+    let stake_vault_account = next_account_info(account_info_iter)?;
+    let withdraw_authority_account = next_account_info(account_info_iter)?;
     let user_stake_account = next_account_info(account_info_iter)?;
+    let token_program_account = next_account_info(account_info_iter)?;
 
     if !user_account.is_signer {
         msg!("User must be a signer");
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let amount = u64::from_le_bytes(instruction_data[1..9].try_into().unwrap());
+    let position_id = instruction_data[1];
+    let amount = u64::from_le_bytes(instruction_data[2..10].try_into().unwrap());
+
+    validate_user_stake_account(
+        program_id,
+        user_account.key,
+        staking_manager_account.key,
+        position_id,
+        user_stake_account.key,
+    )?;
+    validate_token_account(user_token_account, &staking_manager.stake_token)?;
+    validate_token_account(stake_vault_account, &staking_manager.stake_token)?;
+    if *stake_vault_account.key != staking_manager.stake_vault {
+        msg!("Stake vault account does not match the pool's stake vault");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if token_program_account.key != &spl_token::id() {
+        msg!("Token program account does not match the SPL token program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    update_pool(staking_manager)?;
 
     let mut user_stake_info = UserStakeInfo::unpack(&user_stake_account.data.borrow())?;
+    if user_stake_info.user != *user_account.key {
+        msg!("User stake account does not belong to the signer");
+        return Err(ProgramError::IllegalOwner);
+    }
     if user_stake_info.staked_amount < amount {
         msg!("Insufficient staked tokens");
         return Err(ProgramError::InsufficientFunds);
     }
 
+    let now = Clock::get()?.unix_timestamp as u64;
+    if now < user_stake_info.stake_timestamp + staking_manager.lockup_duration {
+        msg!("Stake is still within the lockup period");
+        return Err(StakingError::StakeLocked.into());
+    }
+
+    accrue_pending(staking_manager, &mut user_stake_info);
     user_stake_info.staked_amount -= amount;
+    reset_reward_debt(staking_manager, &mut user_stake_info);
     UserStakeInfo::pack(user_stake_info, &mut user_stake_account.data.borrow_mut())?;
 
-    invoke(
-        &system_instruction::transfer(stake_token_account.key, user_account.key, amount),
-        &[stake_token_account.clone(), user_account.clone()],
+    let manager_key = staking_manager_account.key;
+    let withdraw_authority = authority_id(
+        program_id,
+        manager_key,
+        AUTHORITY_WITHDRAW,
+        staking_manager.withdraw_bump_seed,
+    )?;
+    if withdraw_authority != *withdraw_authority_account.key {
+        msg!("Withdraw authority does not match the derived vault PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    invoke_signed(
+        &token_instruction::transfer(
+            token_program_account.key,
+            stake_vault_account.key,
+            user_token_account.key,
+            withdraw_authority_account.key,
+            &[],
+            amount,
+        )?,
+        &[
+            stake_vault_account.clone(),
+            user_token_account.clone(),
+            withdraw_authority_account.clone(),
+            token_program_account.clone(),
+        ],
+        &[&[
+            manager_key.as_ref(),
+            AUTHORITY_WITHDRAW,
+            &[staking_manager.withdraw_bump_seed],
+        ]],
     )?;
 
     staking_manager.tokens_staked -= amount;
@@ -255,8 +578,8 @@ fn start_epoch(
     let account_info_iter = &mut accounts.iter();
     let owner_account = next_account_info(account_info_iter)?;
 
-    if !owner_account.is_signer {
-        msg!("Owner must be a signer");
+    if !owner_account.is_signer || *owner_account.key != staking_manager.owner {
+        msg!("Owner must sign to start a new epoch");
         return Err(ProgramError::MissingRequiredSignature);
     }
 
@@ -264,6 +587,9 @@ fn start_epoch(
     let end_time = u64::from_le_bytes(instruction_data[9..17].try_into().unwrap());
     let reward_amount = u64::from_le_bytes(instruction_data[17..25].try_into().unwrap());
 
+    // Flush any reward still accruable under the outgoing epoch's window before it's overwritten.
+    update_pool(staking_manager)?;
+
     if start_time <= staking_manager.current_epoch_end_time {
         msg!("Epoch start time must be after the current epoch end time");
         return Err(ProgramError::InvalidArgument);
@@ -274,6 +600,12 @@ fn start_epoch(
         return Err(ProgramError::InvalidArgument);
     }
 
+    let now = Clock::get()?.unix_timestamp as u64;
+    if start_time < now {
+        msg!("Epoch start time cannot be in the past");
+        return Err(ProgramError::InvalidArgument);
+    }
+
     staking_manager.current_epoch_start_time = start_time;
     staking_manager.current_epoch_end_time = end_time;
     staking_manager.current_epoch_reward = reward_amount;
@@ -285,27 +617,95 @@ fn start_epoch(
 }
 
 fn claim(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
     staking_manager: &mut StakingManager,
     instruction_data: &[u8],
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
+    let staking_manager_account = next_account_info(account_info_iter)?;
     let user_account = next_account_info(account_info_iter)?;
-    let reward_token_account = next_account_info(account_info_iter)?;
+    let user_reward_token_account = next_account_info(account_info_iter)?;
+    let reward_vault_account = next_account_info(account_info_iter)?;
+    let withdraw_authority_account = next_account_info(account_info_iter)?;
     let user_stake_account = next_account_info(account_info_iter)?;
+    let token_program_account = next_account_info(account_info_iter)?;
 
     if !user_account.is_signer {
         msg!("User must be a signer");
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let user_staked_amount = get_user_staked_amount(user_stake_account)?;
+    let position_id = instruction_data[1];
 
-    let rewards = calculate_rewards(staking_manager, user_staked_amount)?;
+    validate_user_stake_account(
+        program_id,
+        user_account.key,
+        staking_manager_account.key,
+        position_id,
+        user_stake_account.key,
+    )?;
+    validate_token_account(user_reward_token_account, &staking_manager.reward_token)?;
+    validate_token_account(reward_vault_account, &staking_manager.reward_token)?;
+    if *reward_vault_account.key != staking_manager.reward_vault {
+        msg!("Reward vault account does not match the pool's reward vault");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if token_program_account.key != &spl_token::id() {
+        msg!("Token program account does not match the SPL token program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
 
-    invoke(
-        &system_instruction::transfer(reward_token_account.key, user_account.key, rewards),
-        &[reward_token_account.clone(), user_account.clone()],
+    update_pool(staking_manager)?;
+
+    let mut user_stake_info = UserStakeInfo::unpack(&user_stake_account.data.borrow())?;
+    if !user_stake_info.is_initialized {
+        msg!("User account is not initialized");
+        return Err(ProgramError::UninitializedAccount);
+    }
+    if user_stake_info.user != *user_account.key {
+        msg!("User stake account does not belong to the signer");
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    accrue_pending(staking_manager, &mut user_stake_info);
+    let rewards = user_stake_info.pending_reward;
+    user_stake_info.pending_reward = 0;
+    reset_reward_debt(staking_manager, &mut user_stake_info);
+    UserStakeInfo::pack(user_stake_info, &mut user_stake_account.data.borrow_mut())?;
+
+    let manager_key = staking_manager_account.key;
+    let withdraw_authority = authority_id(
+        program_id,
+        manager_key,
+        AUTHORITY_WITHDRAW,
+        staking_manager.withdraw_bump_seed,
+    )?;
+    if withdraw_authority != *withdraw_authority_account.key {
+        msg!("Withdraw authority does not match the derived vault PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    invoke_signed(
+        &token_instruction::transfer(
+            token_program_account.key,
+            reward_vault_account.key,
+            user_reward_token_account.key,
+            withdraw_authority_account.key,
+            &[],
+            rewards,
+        )?,
+        &[
+            reward_vault_account.clone(),
+            user_reward_token_account.clone(),
+            withdraw_authority_account.clone(),
+            token_program_account.clone(),
+        ],
+        &[&[
+            manager_key.as_ref(),
+            AUTHORITY_WITHDRAW,
+            &[staking_manager.withdraw_bump_seed],
+        ]],
     )?;
 
     msg!("Claimed {} rewards", rewards);
@@ -313,27 +713,394 @@ fn claim(
     Ok(())
 }
 
-fn get_user_staked_amount(user_stake_account: &AccountInfo) -> Result<u64, ProgramError> {
-    let user_stake_info = UserStakeInfo::unpack(&user_stake_account.data.borrow())?;
-    if !user_stake_info.is_initialized {
-        msg!("User account is not initialized");
-        return Err(ProgramError::UninitializedAccount);
+
+/// Moves idle lamports held by the withdraw-authority PDA into the pool's managed stake
+/// account and delegates it to `vote_account`, so the pool earns validator rewards on top
+/// of the epoch reward token. The stake account's staker/withdrawer authorities must
+/// already be set to the withdraw-authority PDA off-chain before this is called.
+///
+/// `deposit`/`unstake` only move SPL tokens into `stake_vault`/`reward_vault`; nothing in
+/// this contract puts native SOL into the withdraw-authority PDA. The lamports delegated
+/// here must arrive via a separate, out-of-band system transfer to that PDA before this is
+/// called.
+fn delegate_all(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    staking_manager: &mut StakingManager,
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let staking_manager_account = next_account_info(account_info_iter)?;
+    let owner_account = next_account_info(account_info_iter)?;
+    let withdraw_authority_account = next_account_info(account_info_iter)?;
+    let stake_account = next_account_info(account_info_iter)?;
+    let vote_account = next_account_info(account_info_iter)?;
+    let clock_sysvar_account = next_account_info(account_info_iter)?;
+    let stake_history_sysvar_account = next_account_info(account_info_iter)?;
+    let stake_config_account = next_account_info(account_info_iter)?;
+    let stake_program_account = next_account_info(account_info_iter)?;
+
+    if !owner_account.is_signer || *owner_account.key != staking_manager.owner {
+        msg!("Owner must sign to delegate pooled stake");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if stake_account.owner != &stake::program::id() {
+        msg!("Stake account is not owned by the native stake program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if *stake_history_sysvar_account.key != stake_history::id() {
+        msg!("Incorrect stake history sysvar account");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let manager_key = staking_manager_account.key;
+    let withdraw_authority = authority_id(
+        program_id,
+        manager_key,
+        AUTHORITY_WITHDRAW,
+        staking_manager.withdraw_bump_seed,
+    )?;
+    if withdraw_authority != *withdraw_authority_account.key {
+        msg!("Withdraw authority does not match the derived vault PDA");
+        return Err(ProgramError::InvalidSeeds);
     }
-    Ok(user_stake_info.staked_amount)
+
+    let lamports = u64::from_le_bytes(instruction_data[1..9].try_into().unwrap());
+    let withdraw_authority_seeds: &[&[u8]] =
+        &[manager_key.as_ref(), AUTHORITY_WITHDRAW, &[staking_manager.withdraw_bump_seed]];
+
+    invoke_signed(
+        &system_instruction::transfer(withdraw_authority_account.key, stake_account.key, lamports),
+        &[withdraw_authority_account.clone(), stake_account.clone()],
+        &[withdraw_authority_seeds],
+    )?;
+
+    invoke_signed(
+        &stake_instruction::delegate_stake(
+            stake_account.key,
+            withdraw_authority_account.key,
+            vote_account.key,
+        ),
+        &[
+            stake_account.clone(),
+            vote_account.clone(),
+            clock_sysvar_account.clone(),
+            stake_history_sysvar_account.clone(),
+            stake_config_account.clone(),
+            withdraw_authority_account.clone(),
+            stake_program_account.clone(),
+        ],
+        &[withdraw_authority_seeds],
+    )?;
+
+    staking_manager.stake_account = *stake_account.key;
+    staking_manager.vote_account = *vote_account.key;
+    msg!("Delegated {} lamports to validator vote account", lamports);
+
+    Ok(())
 }
 
-fn calculate_rewards(
-    staking_manager: &StakingManager,
-    user_staked_amount: u64,
-) -> Result<u64, ProgramError> {
-    if staking_manager.tokens_staked == 0 {
-        return Ok(0);
+/// Begins deactivation (cooldown) of the pool's delegated stake account, as the first step
+/// toward reclaiming it into the withdraw-authority PDA via `reclaim`.
+fn deactivate(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    staking_manager: &mut StakingManager,
+    _instruction_data: &[u8],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let staking_manager_account = next_account_info(account_info_iter)?;
+    let owner_account = next_account_info(account_info_iter)?;
+    let withdraw_authority_account = next_account_info(account_info_iter)?;
+    let stake_account = next_account_info(account_info_iter)?;
+    let clock_sysvar_account = next_account_info(account_info_iter)?;
+    let stake_program_account = next_account_info(account_info_iter)?;
+
+    if !owner_account.is_signer || *owner_account.key != staking_manager.owner {
+        msg!("Owner must sign to deactivate pooled stake");
+        return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let user_share = user_staked_amount as u128 * staking_manager.current_epoch_reward as u128;
-    let total_staked = staking_manager.tokens_staked as u128;
+    if *stake_account.key != staking_manager.stake_account {
+        msg!("Stake account does not match the pool's managed stake account");
+        return Err(ProgramError::InvalidArgument);
+    }
 
-    let user_reward = user_share / total_staked;
+    if stake_account.owner != &stake::program::id() {
+        msg!("Stake account is not owned by the native stake program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
 
-    Ok(user_reward as u64)
-}
\ No newline at end of file
+    let manager_key = staking_manager_account.key;
+    let withdraw_authority = authority_id(
+        program_id,
+        manager_key,
+        AUTHORITY_WITHDRAW,
+        staking_manager.withdraw_bump_seed,
+    )?;
+    if withdraw_authority != *withdraw_authority_account.key {
+        msg!("Withdraw authority does not match the derived vault PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    invoke_signed(
+        &stake_instruction::deactivate_stake(stake_account.key, withdraw_authority_account.key),
+        &[
+            stake_account.clone(),
+            clock_sysvar_account.clone(),
+            withdraw_authority_account.clone(),
+            stake_program_account.clone(),
+        ],
+        &[&[manager_key.as_ref(), AUTHORITY_WITHDRAW, &[staking_manager.withdraw_bump_seed]]],
+    )?;
+
+    msg!("Deactivated pooled stake, cooldown in progress");
+
+    Ok(())
+}
+
+/// Withdraws lamports from a deactivated (cooled-down) stake account back into the
+/// withdraw-authority PDA's reserve, from where `delegate_all` can re-delegate them.
+fn reclaim(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    staking_manager: &mut StakingManager,
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let staking_manager_account = next_account_info(account_info_iter)?;
+    let owner_account = next_account_info(account_info_iter)?;
+    let withdraw_authority_account = next_account_info(account_info_iter)?;
+    let stake_account = next_account_info(account_info_iter)?;
+    let clock_sysvar_account = next_account_info(account_info_iter)?;
+    let stake_history_sysvar_account = next_account_info(account_info_iter)?;
+    let stake_program_account = next_account_info(account_info_iter)?;
+
+    if !owner_account.is_signer || *owner_account.key != staking_manager.owner {
+        msg!("Owner must sign to reclaim pooled stake");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *stake_account.key != staking_manager.stake_account {
+        msg!("Stake account does not match the pool's managed stake account");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if stake_account.owner != &stake::program::id() {
+        msg!("Stake account is not owned by the native stake program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if *stake_history_sysvar_account.key != stake_history::id() {
+        msg!("Incorrect stake history sysvar account");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let manager_key = staking_manager_account.key;
+    let withdraw_authority = authority_id(
+        program_id,
+        manager_key,
+        AUTHORITY_WITHDRAW,
+        staking_manager.withdraw_bump_seed,
+    )?;
+    if withdraw_authority != *withdraw_authority_account.key {
+        msg!("Withdraw authority does not match the derived vault PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let lamports = u64::from_le_bytes(instruction_data[1..9].try_into().unwrap());
+
+    invoke_signed(
+        &stake_instruction::withdraw(
+            stake_account.key,
+            withdraw_authority_account.key,
+            withdraw_authority_account.key,
+            lamports,
+            None,
+        ),
+        &[
+            stake_account.clone(),
+            withdraw_authority_account.clone(),
+            clock_sysvar_account.clone(),
+            stake_history_sysvar_account.clone(),
+            withdraw_authority_account.clone(),
+            stake_program_account.clone(),
+        ],
+        &[&[manager_key.as_ref(), AUTHORITY_WITHDRAW, &[staking_manager.withdraw_bump_seed]]],
+    )?;
+
+    msg!("Reclaimed {} lamports from deactivated stake", lamports);
+
+    Ok(())
+}
+
+/// Moves `amount` out of the signer's primary position (position `0`) into
+/// `dest_position_id`, a secondary `UserStakeInfo` PDA the caller can later `merge` back or
+/// unstake independently. Both positions settle through the accumulator before the balance
+/// moves, so splitting never gains or loses pending reward.
+fn split(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    staking_manager: &mut StakingManager,
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let staking_manager_account = next_account_info(account_info_iter)?;
+    let user_account = next_account_info(account_info_iter)?;
+    let source_user_stake_account = next_account_info(account_info_iter)?;
+    let dest_user_stake_account = next_account_info(account_info_iter)?;
+
+    if !user_account.is_signer {
+        msg!("User must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let dest_position_id = instruction_data[1];
+    if dest_position_id == 0 {
+        msg!("Destination position id must be nonzero");
+        return Err(ProgramError::InvalidArgument);
+    }
+    let amount = u64::from_le_bytes(instruction_data[2..10].try_into().unwrap());
+
+    validate_user_stake_account(
+        program_id,
+        user_account.key,
+        staking_manager_account.key,
+        0,
+        source_user_stake_account.key,
+    )?;
+    validate_user_stake_account(
+        program_id,
+        user_account.key,
+        staking_manager_account.key,
+        dest_position_id,
+        dest_user_stake_account.key,
+    )?;
+
+    update_pool(staking_manager)?;
+
+    let mut source_stake_info = UserStakeInfo::unpack(&source_user_stake_account.data.borrow())?;
+    if source_stake_info.user != *user_account.key {
+        msg!("Source stake account does not belong to the signer");
+        return Err(ProgramError::IllegalOwner);
+    }
+    if source_stake_info.staked_amount < amount {
+        msg!("Insufficient staked tokens in source position");
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    let mut dest_stake_info = UserStakeInfo::unpack_unchecked(&dest_user_stake_account.data.borrow())?;
+    if !dest_stake_info.is_initialized {
+        dest_stake_info.is_initialized = true;
+        dest_stake_info.user = *user_account.key;
+        dest_stake_info.reward_debt = 0;
+        dest_stake_info.pending_reward = 0;
+        // A freshly-created position inherits the source's lockup, so splitting can't be
+        // used to shorten it.
+        dest_stake_info.stake_timestamp = source_stake_info.stake_timestamp;
+    } else if dest_stake_info.user != *user_account.key {
+        msg!("Destination stake account does not belong to the signer");
+        return Err(ProgramError::IllegalOwner);
+    } else {
+        // An existing destination keeps the later of the two lockup expiries, same as
+        // `merge`, so splitting into an older position can't be used to escape lockup early.
+        dest_stake_info.stake_timestamp = dest_stake_info.stake_timestamp.max(source_stake_info.stake_timestamp);
+    }
+
+    accrue_pending(staking_manager, &mut source_stake_info);
+    accrue_pending(staking_manager, &mut dest_stake_info);
+
+    source_stake_info.staked_amount -= amount;
+    dest_stake_info.staked_amount += amount;
+
+    reset_reward_debt(staking_manager, &mut source_stake_info);
+    reset_reward_debt(staking_manager, &mut dest_stake_info);
+
+    UserStakeInfo::pack(source_stake_info, &mut source_user_stake_account.data.borrow_mut())?;
+    UserStakeInfo::pack(dest_stake_info, &mut dest_user_stake_account.data.borrow_mut())?;
+
+    msg!("Split {} tokens into position {}", amount, dest_position_id);
+
+    Ok(())
+}
+
+/// Folds `source_position_id` entirely into `dest_position_id`, combining staked amounts and
+/// pending reward and clearing the source position. The merged position keeps the later of
+/// the two lockup expiries, so merging can't be used to shorten a still-locked stake.
+fn merge(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    staking_manager: &mut StakingManager,
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let staking_manager_account = next_account_info(account_info_iter)?;
+    let user_account = next_account_info(account_info_iter)?;
+    let source_user_stake_account = next_account_info(account_info_iter)?;
+    let dest_user_stake_account = next_account_info(account_info_iter)?;
+
+    if !user_account.is_signer {
+        msg!("User must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let source_position_id = instruction_data[1];
+    let dest_position_id = instruction_data[2];
+    if source_position_id == dest_position_id {
+        msg!("Source and destination positions must differ");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    validate_user_stake_account(
+        program_id,
+        user_account.key,
+        staking_manager_account.key,
+        source_position_id,
+        source_user_stake_account.key,
+    )?;
+    validate_user_stake_account(
+        program_id,
+        user_account.key,
+        staking_manager_account.key,
+        dest_position_id,
+        dest_user_stake_account.key,
+    )?;
+
+    update_pool(staking_manager)?;
+
+    let mut source_stake_info = UserStakeInfo::unpack(&source_user_stake_account.data.borrow())?;
+    if source_stake_info.user != *user_account.key {
+        msg!("Source stake account does not belong to the signer");
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let mut dest_stake_info = UserStakeInfo::unpack(&dest_user_stake_account.data.borrow())?;
+    if dest_stake_info.user != *user_account.key {
+        msg!("Destination stake account does not belong to the signer");
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    accrue_pending(staking_manager, &mut source_stake_info);
+    accrue_pending(staking_manager, &mut dest_stake_info);
+
+    dest_stake_info.staked_amount += source_stake_info.staked_amount;
+    dest_stake_info.pending_reward += source_stake_info.pending_reward;
+    dest_stake_info.stake_timestamp = dest_stake_info.stake_timestamp.max(source_stake_info.stake_timestamp);
+    reset_reward_debt(staking_manager, &mut dest_stake_info);
+
+    source_stake_info.is_initialized = false;
+    source_stake_info.user = Pubkey::default();
+    source_stake_info.staked_amount = 0;
+    source_stake_info.reward_debt = 0;
+    source_stake_info.pending_reward = 0;
+    source_stake_info.stake_timestamp = 0;
+
+    UserStakeInfo::pack(source_stake_info, &mut source_user_stake_account.data.borrow_mut())?;
+    UserStakeInfo::pack(dest_stake_info, &mut dest_user_stake_account.data.borrow_mut())?;
+
+    msg!("Merged position {} into position {}", source_position_id, dest_position_id);
+
+    Ok(())
+}